@@ -1,12 +1,16 @@
 extern crate gif;
+extern crate getopts;
+extern crate ffmpeg_next as ffmpeg;
 
-use std::{ffi, borrow, iter, cmp, fs, io, error, path, fmt};
-use std::io::Write;
+use std::{ffi, borrow, iter, cmp, fs, io, error, path, fmt, env, process as proc_mod, sync};
+use std::io::{Read, Seek, Write};
+use std::collections::HashSet;
 
 #[derive(Debug)]
 enum Err {
     Io(io::Error),
     Gif(gif::DecodingError),
+    Decode(String),
     Usage
 }
 impl error::Error for Err {
@@ -27,7 +31,42 @@ impl fmt::Display for Err {
             Err::Gif(gif::DecodingError::Format(s))
             | Err::Gif(gif::DecodingError::Internal(s)) =>
                 write!(f, "gif decoding error: {}", s),
-            Err::Usage => write!(f, "usage: pass one file.gif, read stdout")
+            Err::Decode(ref s) => write!(f, "decoding error: {}", s),
+            Err::Usage => write!(f, "usage: gif-frame-normalizer [-o FILE] [-r N] \
+                [--min-delay N] [--zero-delay N] FILE.{{gif,mp4,webm,apng}}")
+        }
+    }
+}
+
+/// How many times the output animation should loop, mirrored to `gif::Repeat`.
+#[derive(Debug, Clone, Copy)]
+enum Repeat {
+    Finite(u16),
+    Infinite,
+}
+
+impl Repeat {
+    fn to_gif(self) -> gif::Repeat {
+        match self {
+            Repeat::Finite(n) => gif::Repeat::Finite(n),
+            Repeat::Infinite => gif::Repeat::Infinite,
+        }
+    }
+}
+
+/// Tunables for `process` that used to be hardcoded constants.
+struct Settings {
+    min_delay: u16,
+    zero_delay: u16,
+    repeat: Repeat,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            min_delay: 2,
+            zero_delay: 10,
+            repeat: Repeat::Infinite,
         }
     }
 }
@@ -39,21 +78,69 @@ fn main() {
     }
 
     fn go() -> Result<(), Err> {
-        let mut args = std::env::args_os().skip(1);
-        let arg = match (args.next(), args.next()) {
-            (Some(arg), None) => arg,
-            _ => return Err(Err::Usage)
-        };
+        let args: Vec<String> = std::env::args().collect();
 
-        let path: &path::Path = arg.as_ref();
+        let mut opts = getopts::Options::new();
+        opts.optopt("o", "", "write output to FILE instead of stdout", "FILE");
+        opts.optopt("r", "", "finite loop count (default: loop forever)", "N");
+        opts.optopt("", "min-delay", "minimum output delay in centiseconds", "N");
+        opts.optopt("", "zero-delay", "delay substituted for zero-delay frames", "N");
+        opts.optflag("", "delta", "encode unchanged pixels as transparent deltas");
+        opts.optopt("", "max-colors", "re-quantize to a shared palette of at most N colors", "N");
+
+        let matches = try!(opts.parse(&args[1..]).map_err(|_| Err::Usage));
+
+        let input = match matches.free.len() {
+            1 => path::PathBuf::from(&matches.free[0]),
+            _ => return Err(Err::Usage),
+        };
 
         let file_name = path::Path::new(
-            try!(path.file_name().ok_or(Err::Usage)));
+            try!(input.file_name().ok_or(Err::Usage)));
 
         let ext = file_name.extension().and_then(ffi::OsStr::to_str);
-        if ext != Some("gif") { return Err(Err::Usage); }
+        match ext {
+            Some("gif") | Some("mp4") | Some("webm") | Some("apng") => {}
+            _ => return Err(Err::Usage),
+        }
 
-        try!(process(path));
+        let mut settings = Settings::default();
+
+        if let Some(n) = matches.opt_str("min-delay") {
+            settings.min_delay = try!(n.parse().map_err(|_| Err::Usage));
+        }
+        if let Some(n) = matches.opt_str("zero-delay") {
+            settings.zero_delay = try!(n.parse().map_err(|_| Err::Usage));
+        }
+        if let Some(n) = matches.opt_str("r") {
+            settings.repeat = Repeat::Finite(try!(n.parse().map_err(|_| Err::Usage)));
+        }
+
+        let delta = matches.opt_present("delta");
+
+        let quantizer = MedianCutQuantizer;
+        let max_colors = match matches.opt_str("max-colors") {
+            Some(n) => {
+                let n: usize = try!(n.parse().map_err(|_| Err::Usage));
+                if n < 1 || n > 256 { return Err(Err::Usage); }
+                Some(n)
+            }
+            None => None,
+        };
+        let quantize = max_colors.map(|max_colors| QuantizeSettings {
+            max_colors: max_colors,
+            quantizer: &quantizer,
+        });
+
+        match matches.opt_str("o") {
+            Some(out_path) => {
+                let out = try!(fs::File::create(out_path));
+                try!(process(&input, out, &settings, quantize, delta));
+            }
+            None => {
+                try!(process(&input, io::stdout(), &settings, quantize, delta));
+            }
+        }
 
         Ok(())
     }
@@ -106,53 +193,769 @@ fn swap_transparent(mut frame: gif::Frame) -> gif::Frame {
     frame
 }
 
-fn process<P: AsRef<path::Path>>(from: P) -> Result<bool, Err> {
-    const MIN_DELAY: u16 = 2;
-    const ZERO_DELAY: u16 = 10;
+fn dispose_to_u8(d: gif::DisposalMethod) -> u8 {
+    use gif::DisposalMethod::*;
+    match d {
+        Any => 0,
+        Keep => 1,
+        Background => 2,
+        Previous => 3,
+    }
+}
+
+fn u8_to_dispose(b: u8) -> gif::DisposalMethod {
+    use gif::DisposalMethod::*;
+    match b {
+        1 => Keep,
+        2 => Background,
+        3 => Previous,
+        _ => Any,
+    }
+}
+
+/// Spills decoded frames to a temporary file once the in-memory buffer exceeds a threshold.
+struct FrameScratch {
+    file: fs::File,
+    path: path::PathBuf,
+}
+
+impl FrameScratch {
+    fn create() -> io::Result<FrameScratch> {
+        static COUNTER: sync::atomic::AtomicUsize = sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, sync::atomic::Ordering::Relaxed);
+
+        let mut path = env::temp_dir();
+        path.push(format!("gif-frame-normalizer-{}-{}.scratch", proc_mod::id(), n));
+
+        let file = try!(fs::OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path));
+
+        Ok(FrameScratch { file: file, path: path })
+    }
+
+    fn write_frame(&mut self, frame: &gif::Frame) -> io::Result<()> {
+        try!(self.file.write_all(&frame.delay.to_le_bytes()));
+        try!(self.file.write_all(&frame.left.to_le_bytes()));
+        try!(self.file.write_all(&frame.top.to_le_bytes()));
+        try!(self.file.write_all(&frame.width.to_le_bytes()));
+        try!(self.file.write_all(&frame.height.to_le_bytes()));
+        try!(self.file.write_all(&[dispose_to_u8(frame.dispose)]));
+
+        match frame.transparent {
+            Some(t) => try!(self.file.write_all(&[1, t])),
+            None => try!(self.file.write_all(&[0, 0])),
+        }
+
+        let palette_len = frame.palette.as_ref().map_or(0, |p| p.len()) as u32;
+        try!(self.file.write_all(&palette_len.to_le_bytes()));
+        if let Some(ref palette) = frame.palette {
+            try!(self.file.write_all(palette));
+        }
+
+        let buffer_len = frame.buffer.len() as u32;
+        try!(self.file.write_all(&buffer_len.to_le_bytes()));
+        try!(self.file.write_all(&frame.buffer));
+
+        Ok(())
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        try!(self.file.seek(io::SeekFrom::Start(0)));
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<gif::Frame<'static>>> {
+        let mut u16_buf = [0u8; 2];
+        match self.file.read(&mut u16_buf) {
+            Ok(0) => return Ok(None),
+            Ok(n) => try!(self.file.read_exact(&mut u16_buf[n..])),
+            Err(e) => return Err(e),
+        }
+        let delay = u16::from_le_bytes(u16_buf);
+
+        let left = try!(self.read_u16());
+        let top = try!(self.read_u16());
+        let width = try!(self.read_u16());
+        let height = try!(self.read_u16());
+
+        let mut dispose_buf = [0u8; 1];
+        try!(self.file.read_exact(&mut dispose_buf));
+        let dispose = u8_to_dispose(dispose_buf[0]);
+
+        let mut transparent_buf = [0u8; 2];
+        try!(self.file.read_exact(&mut transparent_buf));
+        let transparent = if transparent_buf[0] == 1 { Some(transparent_buf[1]) } else { None };
+
+        let palette_len = try!(self.read_u32()) as usize;
+        let palette = if palette_len > 0 {
+            let mut buf = vec![0u8; palette_len];
+            try!(self.file.read_exact(&mut buf));
+            Some(buf)
+        } else {
+            None
+        };
+
+        let buffer_len = try!(self.read_u32()) as usize;
+        let mut buffer = vec![0u8; buffer_len];
+        try!(self.file.read_exact(&mut buffer));
+
+        Ok(Some(gif::Frame {
+            delay: delay,
+            left: left,
+            top: top,
+            width: width,
+            height: height,
+            dispose: dispose,
+            transparent: transparent,
+            palette: palette,
+            buffer: borrow::Cow::Owned(buffer),
+            .. Default::default()
+        }))
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        try!(self.file.read_exact(&mut buf));
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        try!(self.file.read_exact(&mut buf));
+        Ok(u32::from_le_bytes(buf))
+    }
+}
+
+impl Drop for FrameScratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Builds a palette of at most `max_colors` entries approximating `colors`.
+trait Quantizer {
+    fn build_palette(&self, colors: &[[u8; 3]], max_colors: usize) -> Vec<[u8; 3]>;
+}
+
+/// Knobs for the optional global re-quantization pass.
+struct QuantizeSettings<'a> {
+    max_colors: usize,
+    quantizer: &'a Quantizer,
+}
+
+/// Median-cut quantizer: splits the widest box along its widest channel until
+/// there are enough boxes, then averages each one down to a palette entry.
+struct MedianCutQuantizer;
+
+impl Quantizer for MedianCutQuantizer {
+    fn build_palette(&self, colors: &[[u8; 3]], max_colors: usize) -> Vec<[u8; 3]> {
+        if colors.is_empty() || max_colors == 0 { return Vec::new(); }
+        if colors.len() <= max_colors { return colors.to_vec(); }
+
+        let mut boxes = vec![colors.to_vec()];
+        while boxes.len() < max_colors {
+            let split = match widest_box(&boxes) {
+                Some(i) => i,
+                None => break,
+            };
+
+            let channel = widest_channel(&boxes[split]);
+            let mut bx = boxes.swap_remove(split);
+            bx.sort_by_key(|c| c[channel]);
+            let hi = bx.split_off(bx.len() / 2);
+            boxes.push(bx);
+            boxes.push(hi);
+        }
+
+        boxes.iter().map(|bx| average_color(bx)).collect()
+    }
+}
+
+fn widest_box(boxes: &[Vec<[u8; 3]>]) -> Option<usize> {
+    boxes.iter()
+        .enumerate()
+        .filter(|&(_, bx)| bx.len() >= 2)
+        .max_by_key(|&(_, bx)| channel_range(bx, widest_channel(bx)))
+        .map(|(i, _)| i)
+}
+
+fn widest_channel(colors: &[[u8; 3]]) -> usize {
+    (0..3).max_by_key(|&c| channel_range(colors, c)).unwrap_or(0)
+}
+
+fn channel_range(colors: &[[u8; 3]], channel: usize) -> u8 {
+    let lo = colors.iter().map(|c| c[channel]).min().unwrap_or(0);
+    let hi = colors.iter().map(|c| c[channel]).max().unwrap_or(0);
+    hi - lo
+}
+
+fn average_color(colors: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u32; 3];
+    for c in colors {
+        for k in 0..3 { sum[k] += c[k] as u32; }
+    }
+    let n = cmp::max(colors.len() as u32, 1);
+    [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]
+}
+
+fn color_distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    let dr = a[0] as i32 - b[0] as i32;
+    let dg = a[1] as i32 - b[1] as i32;
+    let db = a[2] as i32 - b[2] as i32;
+    (dr * dr + dg * dg + db * db) as u32
+}
+
+/// Smallest valid GIF color-table size (a power of two from 2 to 256) holding at least `entries` colors.
+fn palette_table_size(entries: usize) -> usize {
+    let mut size = 2;
+    while size < entries && size < 256 { size *= 2; }
+    size
+}
+
+fn nearest_index(color: [u8; 3], palette: &[[u8; 3]]) -> u8 {
+    palette.iter()
+        .enumerate()
+        .min_by_key(|&(_, &p)| color_distance(color, p))
+        .map(|(i, _)| i as u8)
+        .unwrap_or(0)
+}
+
+/// Collects the distinct RGB colors referenced across every frame (in memory or spilled to `scratch`).
+fn collect_colors(
+    buffered: &[gif::Frame],
+    scratch: &mut Option<FrameScratch>,
+    global_palette: &[u8],
+) -> Result<Vec<[u8; 3]>, Err> {
+    let mut seen = HashSet::new();
+
+    let mut visit = |frame: &gif::Frame| {
+        let palette: &[u8] = frame.palette.as_ref().map_or(global_palette, |p| p.as_slice());
+        for &idx in frame.buffer.iter() {
+            let p = idx as usize * 3;
+            if p + 2 < palette.len() {
+                seen.insert([palette[p], palette[p + 1], palette[p + 2]]);
+            }
+        }
+    };
+
+    match *scratch {
+        Some(ref mut s) => {
+            try!(s.rewind());
+            while let Some(frame) = try!(s.read_frame()) {
+                visit(&frame);
+            }
+        }
+        None => {
+            for frame in buffered { visit(frame); }
+        }
+    }
+
+    Ok(seen.into_iter().collect())
+}
+
+/// Remaps a frame's buffer onto `new_palette`, dropping the now-redundant local palette.
+fn remap_frame(frame: &mut gif::Frame, global_palette: &[u8], new_palette: &[[u8; 3]]) {
+    let local_palette = frame.palette.take();
+    let palette: &[u8] = local_palette.as_ref().map_or(global_palette, |p| p.as_slice());
+
+    let entries = palette.len() / 3;
+    let mut table = vec![0u8; entries];
+    for i in 0..entries {
+        let p = i * 3;
+        table[i] = nearest_index([palette[p], palette[p + 1], palette[p + 2]], new_palette);
+    }
+
+    for idx in frame.buffer.to_mut().iter_mut() {
+        if let Some(&mapped) = table.get(*idx as usize) {
+            *idx = mapped;
+        }
+    }
+
+    if let Some(t) = frame.transparent {
+        if let Some(&mapped) = table.get(t as usize) {
+            frame.transparent = Some(mapped);
+        }
+    }
+}
+
+fn palette_to_colors(palette: &[u8]) -> Vec<[u8; 3]> {
+    palette.chunks(3)
+        .map(|c| [c[0], *c.get(1).unwrap_or(&0), *c.get(2).unwrap_or(&0)])
+        .collect()
+}
+
+/// Draws `frame`'s opaque pixels onto a full-screen `canvas`.
+fn composite_onto(canvas: &mut [u8], canvas_w: usize, canvas_h: usize, frame: &gif::Frame) {
+    for y in 0..frame.height as usize {
+        let cy = frame.top as usize + y;
+        if cy >= canvas_h { continue; }
+        for x in 0..frame.width as usize {
+            let cx = frame.left as usize + x;
+            if cx >= canvas_w { continue; }
+            let idx = frame.buffer[y * frame.width as usize + x];
+            if Some(idx) == frame.transparent { continue; }
+            canvas[cy * canvas_w + cx] = idx;
+        }
+    }
+}
+
+/// Applies `frame`'s disposal method to `canvas` ahead of the next frame.
+fn apply_disposal(
+    canvas: &mut [u8],
+    canvas_w: usize,
+    canvas_h: usize,
+    frame: &gif::Frame,
+    pre_draw: &[u8],
+    background: u8,
+) {
+    use gif::DisposalMethod::*;
+    match frame.dispose {
+        Background => {
+            for y in 0..frame.height as usize {
+                let cy = frame.top as usize + y;
+                if cy >= canvas_h { continue; }
+                for x in 0..frame.width as usize {
+                    let cx = frame.left as usize + x;
+                    if cx >= canvas_w { continue; }
+                    canvas[cy * canvas_w + cx] = background;
+                }
+            }
+        }
+        Previous => canvas.copy_from_slice(pre_draw),
+        Any | Keep => {}
+    }
+}
+
+/// A source of already-indexed frames for `encode_frames`. `VideoFrameSource`
+/// is the only implementation; GIFs go through `process_gif`'s own pipeline
+/// instead, since they already carry a usable per-frame palette.
+trait FrameSource {
+    fn width(&self) -> u16;
+    fn height(&self) -> u16;
+    fn next_frame(&mut self) -> Result<Option<(Vec<u8>, Vec<[u8; 3]>, u16, gif::DisposalMethod)>, Err>;
+}
+
+// Delay substituted for the first decoded frame, which has no previous pts
+// to diff against.
+const DEFAULT_VIDEO_DELAY: u16 = 10;
+
+/// Decodes a video/animated container via ffmpeg, quantizing each frame so it
+/// flows through the `FrameSource` pipeline.
+struct VideoFrameSource {
+    input: ffmpeg::format::context::Input,
+    decoder: ffmpeg::codec::decoder::Video,
+    scaler: ffmpeg::software::scaling::Context,
+    stream_index: usize,
+    time_base: ffmpeg::Rational,
+    width: u16,
+    height: u16,
+    last_pts: Option<i64>,
+}
+
+impl VideoFrameSource {
+    fn open<P: AsRef<path::Path>>(path: P) -> Result<VideoFrameSource, Err> {
+        let input = try!(ffmpeg::format::input(&path).map_err(|e| Err::Decode(e.to_string())));
+
+        let stream_index;
+        let time_base;
+        let decoder;
+        {
+            let stream = try!(input.streams().best(ffmpeg::media::Type::Video)
+                .ok_or_else(|| Err::Decode("no video stream found".to_owned())));
+            stream_index = stream.index();
+            time_base = stream.time_base();
+
+            let context = try!(ffmpeg::codec::context::Context::from_parameters(stream.parameters())
+                .map_err(|e| Err::Decode(e.to_string())));
+            decoder = try!(context.decoder().video().map_err(|e| Err::Decode(e.to_string())));
+        }
+
+        let width = decoder.width() as u16;
+        let height = decoder.height() as u16;
+
+        let scaler = try!(ffmpeg::software::scaling::Context::get(
+            decoder.format(), decoder.width(), decoder.height(),
+            ffmpeg::format::Pixel::RGB24, decoder.width(), decoder.height(),
+            ffmpeg::software::scaling::Flags::BILINEAR)
+            .map_err(|e| Err::Decode(e.to_string())));
+
+        Ok(VideoFrameSource {
+            input: input,
+            decoder: decoder,
+            scaler: scaler,
+            stream_index: stream_index,
+            time_base: time_base,
+            width: width,
+            height: height,
+            last_pts: None,
+        })
+    }
+
+    fn quantize_frame(&mut self, decoded: &ffmpeg::util::frame::Video)
+        -> Result<(Vec<u8>, Vec<[u8; 3]>, u16, gif::DisposalMethod), Err>
+    {
+        let mut rgb = ffmpeg::util::frame::Video::empty();
+        try!(self.scaler.run(decoded, &mut rgb).map_err(|e| Err::Decode(e.to_string())));
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let stride = rgb.stride(0);
+        let data = rgb.data(0);
+
+        let mut colors = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = &data[y * stride..y * stride + width * 3];
+            for x in 0..width {
+                colors.push([row[x * 3], row[x * 3 + 1], row[x * 3 + 2]]);
+            }
+        }
+
+        let unique: Vec<[u8; 3]> = colors.iter().cloned().collect::<HashSet<_>>().into_iter().collect();
+        let palette = MedianCutQuantizer.build_palette(&unique, 256);
+        let buffer: Vec<u8> = colors.iter().map(|&c| nearest_index(c, &palette)).collect();
+
+        let pts = decoded.pts().unwrap_or(0);
+        let delay = match self.last_pts {
+            Some(last) => {
+                let centiseconds = (pts - last).saturating_mul(self.time_base.numerator() as i64) * 100
+                    / cmp::max(self.time_base.denominator() as i64, 1);
+                cmp::min(cmp::max(centiseconds, 0), u16::max_value() as i64) as u16
+            }
+            None => DEFAULT_VIDEO_DELAY,
+        };
+        self.last_pts = Some(pts);
+
+        Ok((buffer, palette, delay, gif::DisposalMethod::Any))
+    }
+}
+
+impl FrameSource for VideoFrameSource {
+    fn width(&self) -> u16 { self.width }
+    fn height(&self) -> u16 { self.height }
+
+    fn next_frame(&mut self) -> Result<Option<(Vec<u8>, Vec<[u8; 3]>, u16, gif::DisposalMethod)>, Err> {
+        let mut decoded = ffmpeg::util::frame::Video::empty();
+
+        loop {
+            if self.decoder.receive_frame(&mut decoded).is_ok() {
+                return self.quantize_frame(&decoded).map(Some);
+            }
+
+            match self.input.packets().next() {
+                Some((stream, packet)) => {
+                    if stream.index() == self.stream_index {
+                        try!(self.decoder.send_packet(&packet).map_err(|e| Err::Decode(e.to_string())));
+                    }
+                }
+                None => {
+                    try!(self.decoder.send_eof().map_err(|e| Err::Decode(e.to_string())));
+                    if self.decoder.receive_frame(&mut decoded).is_ok() {
+                        return self.quantize_frame(&decoded).map(Some);
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Bounding rectangle of pixels that differ between two canvases, or `None` if identical.
+fn diff_rect(prev: &[u8], current: &[u8], width: usize, height: usize) -> Option<(usize, usize, usize, usize)> {
+    let mut min_x = width;
+    let mut max_x = 0;
+    let mut min_y = height;
+    let mut max_y = 0;
+    let mut any = false;
+
+    for y in 0..height {
+        for x in 0..width {
+            if prev[y * width + x] != current[y * width + x] {
+                any = true;
+                if x < min_x { min_x = x; }
+                if x > max_x { max_x = x; }
+                if y < min_y { min_y = y; }
+                if y > max_y { max_y = y; }
+            }
+        }
+    }
+
+    if !any { return None; }
+    Some((min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+/// Shrinks a frame to the bounding box of pixels changed since `prev`, marking
+/// unchanged ones `transparent_index` so `DisposalMethod::Keep` reveals them.
+fn delta_frame(
+    prev: &[u8],
+    current: &[u8],
+    width: usize,
+    delay: u16,
+    transparent_index: Option<u8>,
+    rect: Option<(usize, usize, usize, usize)>,
+) -> gif::Frame<'static> {
+    let (left, top, w, h) = rect.unwrap_or((0, 0, 1, 1));
+
+    let mut buffer = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let cx = left + x;
+            let cy = top + y;
+            let value = current[cy * width + cx];
+            let unchanged = value == prev[cy * width + cx];
+            buffer.push(match transparent_index {
+                Some(t) if unchanged => t,
+                _ => value,
+            });
+        }
+    }
+
+    gif::Frame {
+        left: left as u16,
+        top: top as u16,
+        width: w as u16,
+        height: h as u16,
+        dispose: gif::DisposalMethod::Keep,
+        transparent: transparent_index,
+        delay: delay,
+        buffer: borrow::Cow::Owned(buffer),
+        .. Default::default()
+    }
+}
+
+/// Tracks the full-screen canvas so each frame can be turned into a minimal delta.
+struct DeltaState {
+    width: usize,
+    height: usize,
+    background: u8,
+    base: Vec<u8>,
+    pre_draw: Vec<u8>,
+    displayed: Option<Vec<u8>>,
+}
+
+impl DeltaState {
+    fn new(width: usize, height: usize, background: u8) -> DeltaState {
+        let canvas = vec![background; width * height];
+        DeltaState {
+            width: width,
+            height: height,
+            background: background,
+            base: canvas.clone(),
+            pre_draw: canvas,
+            displayed: None,
+        }
+    }
+
+    fn transform(&mut self, frame: gif::Frame, transparent_index: Option<u8>) -> gif::Frame<'static> {
+        self.pre_draw = self.base.clone();
+        composite_onto(&mut self.base, self.width, self.height, &frame);
+        let current = self.base.clone();
+
+        let prev = self.displayed.take();
+        apply_disposal(&mut self.base, self.width, self.height, &frame, &self.pre_draw, self.background);
+        self.displayed = Some(current.clone());
+
+        match prev {
+            None => {
+                let buffer = frame.buffer.clone().into_owned();
+                gif::Frame { buffer: borrow::Cow::Owned(buffer), .. frame }
+            }
+            Some(ref prev_canvas) => {
+                let rect = diff_rect(prev_canvas, &current, self.width, self.height);
+                delta_frame(prev_canvas, &current, self.width, frame.delay, transparent_index, rect)
+            }
+        }
+    }
+}
+
+fn emit_frame<W: io::Write>(
+    encoder: &mut gif::Encoder<W>,
+    mut frame: gif::Frame,
+    delay: u16,
+    zero_delay: u16,
+    empty_frame: &gif::Frame,
+) -> Result<(), Err> {
+    let n;
+    if frame.delay < 2 {
+        n = (zero_delay + delay - 1) / delay;
+    } else {
+        n = (frame.delay + delay - 1) / delay;
+    }
+    let n = n as usize;
+    frame.delay = delay;
+
+    let first_frame;
+    let mut i1;
+    let mut i2;
+    let mut i3;
+    let frames: &mut Iterator<Item = &gif::Frame>;
+    frames = if n < 3 {
+        i1 = iter::repeat(&frame).take(n);
+        &mut i1
+    } else {
+        use gif::DisposalMethod::*;
+
+        match frame.dispose {
+            Any | Keep => {
+                i2 = iter::once(&frame)
+                    .chain(iter::repeat(empty_frame))
+                    .take(n);
+                &mut i2
+            }
+            Background => {
+                first_frame = gif::Frame {
+                    dispose: Keep,
+                    .. frame.clone()
+                };
+                i3 = iter::once(&first_frame)
+                    .chain(iter::repeat(empty_frame))
+                    .take(n - 1)
+                    .chain(iter::once(&frame));
+                &mut i3
+            }
+            Previous => {
+                i1 = iter::repeat(&frame).take(n);
+                &mut i1
+            }
+        }
+    };
+
+    for frame in frames {
+        try!(encoder.write_frame(frame));
+    }
+
+    Ok(())
+}
+
+/// Opens `from` and dispatches on its extension: GIFs go through
+/// `process_gif`'s own decoder-driven pipeline (a lossless pass-through
+/// when neither `quantize` nor `delta` is requested), video containers
+/// through `encode_frames` over a `VideoFrameSource`, which always needs
+/// its own quantization since each video frame carries only its own local
+/// palette and there's no global palette to fall back on.
+fn process<P: AsRef<path::Path>, W: io::Write>(
+    from: P,
+    out: W,
+    settings: &Settings,
+    quantize: Option<QuantizeSettings>,
+    delta: bool,
+) -> Result<bool, Err> {
+    let path = from.as_ref();
+    let ext = path.extension().and_then(ffi::OsStr::to_str);
+
+    match ext {
+        Some("gif") => process_gif(path, out, settings, quantize, delta),
+        Some("mp4") | Some("webm") | Some("apng") => {
+            let source = try!(VideoFrameSource::open(path));
+            encode_frames(source, out, settings, quantize, delta)
+        }
+        _ => Err(Err::Usage),
+    }
+}
+
+fn process_gif<P: AsRef<path::Path>, W: io::Write>(
+    from: P,
+    out: W,
+    settings: &Settings,
+    quantize: Option<QuantizeSettings>,
+    delta: bool,
+) -> Result<bool, Err> {
+    let min_delay = settings.min_delay;
+    let zero_delay = settings.zero_delay;
+    // Above this many buffered frames we spill to a scratch file instead of
+    // holding the whole decoded sequence in RAM.
+    const MAX_BUFFERED_FRAMES: usize = 512;
 
     let mut decoder = try!(gif::Decoder::new(
         try!(fs::File::open(from))).read_info());
 
-    let mut frames = Vec::new();
+    let mut buffered = Vec::new();
+    let mut scratch: Option<FrameScratch> = None;
 
     let mut delay;
     let mut any_different = false;
 
     if let Some(first_frame) = try!(decoder.read_next_frame()) {
         delay = first_frame.delay;
-        frames.push(swap_transparent(first_frame.clone()));
+        buffered.push(swap_transparent(first_frame.clone()));
     } else {
         return Ok(false);
     }
 
+    // Pass one: accumulate the delay GCD, buffering frames in memory until
+    // the threshold is crossed, then spilling everything seen so far (and
+    // every frame after) into an on-disk scratch file.
     while let Some(frame) = try!(decoder.read_next_frame()) {
         if delay != frame.delay {
-            delay = gcd(delay, cmp::max(frame.delay, MIN_DELAY));
+            delay = gcd(delay, cmp::max(frame.delay, min_delay));
             any_different = true;
         }
-        frames.push(swap_transparent(frame.clone()));
+        let frame = swap_transparent(frame.clone());
+
+        if scratch.is_none() && buffered.len() >= MAX_BUFFERED_FRAMES {
+            let mut s = try!(FrameScratch::create());
+            for buffered_frame in buffered.drain(..) {
+                try!(s.write_frame(&buffered_frame));
+            }
+            scratch = Some(s);
+        }
+
+        match scratch {
+            Some(ref mut s) => try!(s.write_frame(&frame)),
+            None => buffered.push(frame),
+        }
     }
 
     if !any_different { return Ok(false); }
 
     let global_bg = decoder.bg_color().unwrap_or(0);
-    let mut global_palette_swapped;
-    let global_palette = decoder.global_palette().unwrap_or(&[]);
-    let global_palette =
-        if global_bg == 0 {
-            global_palette
+    let mut global_palette = decoder.global_palette().unwrap_or(&[]).to_owned();
+    if global_bg != 0 {
+        swap_transparent_palette(global_bg, &mut global_palette);
+    }
+
+    let quantized_palette = match quantize {
+        Some(ref q) => {
+            let colors = try!(collect_colors(&buffered, &mut scratch, &global_palette));
+            Some(q.quantizer.build_palette(&colors, q.max_colors))
+        }
+        None => None,
+    };
+
+    // The delta pass needs every frame's buffer indexed into one shared
+    // palette to composite them onto a common canvas, so it forces the same
+    // remap the re-quantization pass uses even when quantization itself is
+    // off (`palette_colors` is then just `global_palette` in RGB triples).
+    let palette_colors = match quantized_palette {
+        Some(ref p) => p.clone(),
+        None => palette_to_colors(&global_palette),
+    };
+    let remap = quantize.is_some() || delta;
+
+    let mut encoder_palette: Vec<u8> = palette_colors.iter().flat_map(|c| c.iter().cloned()).collect();
+
+    // Reserve one spare palette slot for the delta pass's transparent
+    // "unchanged" marker, if the palette isn't already fully saturated.
+    let delta_transparent_index =
+        if delta && palette_colors.len() < 256 {
+            let index = palette_colors.len() as u8;
+            encoder_palette.extend_from_slice(&[0, 0, 0]);
+            Some(index)
         } else {
-            global_palette_swapped = global_palette.to_owned();
-            swap_transparent_palette(global_bg, &mut global_palette_swapped);
-            global_palette_swapped.as_slice()
+            None
         };
 
-    let mut encoder = try!(gif::Encoder::new(io::stdout(),
-        decoder.width(), decoder.height(), global_palette));
+    // `gif::Encoder` writes the color table's size into a 3-bit field, so it
+    // must land on a power of two; pad with black entries up to the next one.
+    let padded_entries = palette_table_size(encoder_palette.len() / 3);
+    encoder_palette.resize(padded_entries * 3, 0);
 
-    try!(gif::SetParameter::set(&mut encoder, gif::Repeat::Infinite));
+    let mut encoder = try!(gif::Encoder::new(out,
+        decoder.width(), decoder.height(), &encoder_palette));
 
-    if delay < MIN_DELAY { delay = MIN_DELAY; }
+    try!(gif::SetParameter::set(&mut encoder, settings.repeat.to_gif()));
+
+    if delay < min_delay { delay = min_delay; }
 
     let empty_buf = [0];
     let empty_frame = gif::Frame {
@@ -164,54 +967,188 @@ fn process<P: AsRef<path::Path>>(from: P) -> Result<bool, Err> {
         .. Default::default()
     };
 
-    for mut frame in frames {
-        let n;
-        if frame.delay < 2 {
-            n = (ZERO_DELAY + delay - 1) / delay;
-        } else {
-            n = (frame.delay + delay - 1) / delay;
-        }
-        let n = n as usize;
-        frame.delay = delay;
-
-        let first_frame;
-        let mut i1;
-        let mut i2;
-        let mut i3;
-        let frames: &mut Iterator<Item = &gif::Frame>;
-        frames = if n < 3 {
-            i1 = iter::repeat(&frame).take(n);
-            &mut i1
+    let mut delta_state =
+        if delta {
+            Some(DeltaState::new(decoder.width() as usize, decoder.height() as usize, 0))
         } else {
-            use gif::DisposalMethod::*;
-
-            match frame.dispose {
-                Any | Keep => {
-                    i2 = iter::once(&frame)
-                        .chain(iter::repeat(&empty_frame))
-                        .take(n);
-                    &mut i2
-                }
-                Background => {
-                    first_frame = gif::Frame {
-                        dispose: Keep,
-                        .. frame.clone()
-                    };
-                    i3 = iter::once(&first_frame)
-                        .chain(iter::repeat(&empty_frame))
-                        .take(n - 1)
-                        .chain(iter::once(&frame));
-                    &mut i3
-                }
-                Previous => {
-                    i1 = iter::repeat(&frame).take(n);
-                    &mut i1
-                }
+            None
+        };
+
+    // Pass two: stream frames back out, either from memory or by rewinding
+    // the scratch file, remap/delta-encode each, and expand it into the
+    // encoder's output frames.
+    match scratch {
+        Some(mut s) => {
+            try!(s.rewind());
+            while let Some(mut frame) = try!(s.read_frame()) {
+                if remap { remap_frame(&mut frame, &global_palette, &palette_colors); }
+                let frame = match delta_state {
+                    Some(ref mut state) => state.transform(frame, delta_transparent_index),
+                    None => frame,
+                };
+                try!(emit_frame(&mut encoder, frame, delay, zero_delay, &empty_frame));
+            }
+        }
+        None => {
+            for mut frame in buffered {
+                if remap { remap_frame(&mut frame, &global_palette, &palette_colors); }
+                let frame = match delta_state {
+                    Some(ref mut state) => state.transform(frame, delta_transparent_index),
+                    None => frame,
+                };
+                try!(emit_frame(&mut encoder, frame, delay, zero_delay, &empty_frame));
             }
+        }
+    }
+
+    Ok(true)
+}
+
+fn encode_frames<S: FrameSource, W: io::Write>(
+    mut source: S,
+    out: W,
+    settings: &Settings,
+    quantize: Option<QuantizeSettings>,
+    delta: bool,
+) -> Result<bool, Err> {
+    let min_delay = settings.min_delay;
+    let zero_delay = settings.zero_delay;
+    // Above this many buffered frames we spill to a scratch file instead of
+    // holding the whole decoded sequence in RAM.
+    const MAX_BUFFERED_FRAMES: usize = 512;
+
+    let width = source.width();
+    let height = source.height();
+
+    let to_frame = |buffer: Vec<u8>, palette: Vec<[u8; 3]>, delay: u16, dispose: gif::DisposalMethod| {
+        gif::Frame {
+            left: 0,
+            top: 0,
+            width: width,
+            height: height,
+            dispose: dispose,
+            delay: delay,
+            palette: Some(palette.iter().flat_map(|c| c.iter().cloned()).collect()),
+            buffer: borrow::Cow::Owned(buffer),
+            .. Default::default()
+        }
+    };
+
+    let mut buffered = Vec::new();
+    let mut scratch: Option<FrameScratch> = None;
+
+    let mut delay;
+    let mut any_different = false;
+
+    match try!(source.next_frame()) {
+        Some((buffer, palette, d, dispose)) => {
+            delay = d;
+            buffered.push(to_frame(buffer, palette, d, dispose));
+        }
+        None => return Ok(false),
+    }
+
+    // Pass one: accumulate the delay GCD, buffering frames in memory until
+    // the threshold is crossed, then spilling everything seen so far (and
+    // every frame after) into an on-disk scratch file.
+    while let Some((buffer, palette, d, dispose)) = try!(source.next_frame()) {
+        if delay != d {
+            delay = gcd(delay, cmp::max(d, min_delay));
+            any_different = true;
+        }
+        let frame = to_frame(buffer, palette, d, dispose);
+
+        if scratch.is_none() && buffered.len() >= MAX_BUFFERED_FRAMES {
+            let mut s = try!(FrameScratch::create());
+            for buffered_frame in buffered.drain(..) {
+                try!(s.write_frame(&buffered_frame));
+            }
+            scratch = Some(s);
+        }
+
+        match scratch {
+            Some(ref mut s) => try!(s.write_frame(&frame)),
+            None => buffered.push(frame),
+        }
+    }
+
+    if !any_different { return Ok(false); }
+
+    // Every `FrameSource` frame already carries its own palette, so there's no
+    // decoder-level global palette to fall back on.
+    let global_palette: Vec<u8> = Vec::new();
+
+    let colors = try!(collect_colors(&buffered, &mut scratch, &global_palette));
+    let palette_colors = match quantize {
+        Some(ref q) => q.quantizer.build_palette(&colors, q.max_colors),
+        None => MedianCutQuantizer.build_palette(&colors, 256),
+    };
+
+    let mut encoder_palette: Vec<u8> = palette_colors.iter().flat_map(|c| c.iter().cloned()).collect();
+
+    // Reserve one spare palette slot for the delta pass's transparent
+    // "unchanged" marker, if the palette isn't already fully saturated.
+    let delta_transparent_index =
+        if delta && palette_colors.len() < 256 {
+            let index = palette_colors.len() as u8;
+            encoder_palette.extend_from_slice(&[0, 0, 0]);
+            Some(index)
+        } else {
+            None
+        };
+
+    // `gif::Encoder` writes the color table's size into a 3-bit field, so it
+    // must land on a power of two; pad with black entries up to the next one.
+    let padded_entries = palette_table_size(encoder_palette.len() / 3);
+    encoder_palette.resize(padded_entries * 3, 0);
+
+    let mut encoder = try!(gif::Encoder::new(out, width, height, &encoder_palette));
+
+    try!(gif::SetParameter::set(&mut encoder, settings.repeat.to_gif()));
+
+    if delay < min_delay { delay = min_delay; }
+
+    let empty_buf = [0];
+    let empty_frame = gif::Frame {
+        delay: delay,
+        width: 1,
+        height: 1,
+        transparent: Some(0),
+        buffer: borrow::Cow::Borrowed(&empty_buf),
+        .. Default::default()
+    };
+
+    let mut delta_state =
+        if delta {
+            Some(DeltaState::new(width as usize, height as usize, 0))
+        } else {
+            None
         };
 
-        for frame in frames {
-            try!(encoder.write_frame(frame));
+    // Pass two: stream frames back out, either from memory or by rewinding
+    // the scratch file, remap/delta-encode each, and expand it into the
+    // encoder's output frames.
+    match scratch {
+        Some(mut s) => {
+            try!(s.rewind());
+            while let Some(mut frame) = try!(s.read_frame()) {
+                remap_frame(&mut frame, &global_palette, &palette_colors);
+                let frame = match delta_state {
+                    Some(ref mut state) => state.transform(frame, delta_transparent_index),
+                    None => frame,
+                };
+                try!(emit_frame(&mut encoder, frame, delay, zero_delay, &empty_frame));
+            }
+        }
+        None => {
+            for mut frame in buffered {
+                remap_frame(&mut frame, &global_palette, &palette_colors);
+                let frame = match delta_state {
+                    Some(ref mut state) => state.transform(frame, delta_transparent_index),
+                    None => frame,
+                };
+                try!(emit_frame(&mut encoder, frame, delay, zero_delay, &empty_frame));
+            }
         }
     }
 